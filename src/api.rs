@@ -3,15 +3,17 @@ use axum::{
     body::Body,
     extract::{Path, State},
     http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
 };
 use log::debug;
 
 use crate::{
-    RequestChannel, ResponseMap, RuntimeGenerator, RuntimeManager, RuntimeProcessGenerator,
-    invocation_next_handler, invocation_response_handler, rambda_handler,
+    ChunkBody, InvocationOutcome, RequestChannel, ResponseMap, RuntimeGenerator, RuntimeManager,
+    RuntimeProcessGenerator, config::Config, invocation_error_handler, invocation_next_handler,
+    invocation_response_handler, rambda_handler,
     types::{
-        ErrorResponse, EventResponse, InvocationNextResponse, InvocationResponse, RequestEvent,
-        StatusResponse,
+        ErrorResponse, EventErrorRequest, EventResponse, InvocationNextResponse,
+        InvocationResponse, PoolStatus, RequestEvent, StatusResponse,
     },
 };
 
@@ -19,23 +21,32 @@ pub struct AppState<G: RuntimeGenerator> {
     request_chan: RequestChannel,
     response_map: ResponseMap,
     runtime_manager: RuntimeManager<G>,
+    config: Config,
 }
 
-impl AppState<RuntimeProcessGenerator> {
-    pub fn new(manager: RuntimeManager<RuntimeProcessGenerator>) -> Self {
+impl<G: RuntimeGenerator> AppState<G> {
+    pub fn with_config(manager: RuntimeManager<G>, config: Config) -> Self {
+        let request_chan = RequestChannel::new(manager.max_concurrency());
         AppState {
-            request_chan: RequestChannel::default(),
+            request_chan,
             response_map: ResponseMap::default(),
             runtime_manager: manager,
+            config,
         }
     }
 }
-impl Clone for AppState<RuntimeProcessGenerator> {
+impl AppState<RuntimeProcessGenerator> {
+    pub fn new(manager: RuntimeManager<RuntimeProcessGenerator>) -> Self {
+        Self::with_config(manager, Config::from_env())
+    }
+}
+impl<G: RuntimeGenerator> Clone for AppState<G> {
     fn clone(&self) -> Self {
         AppState {
             request_chan: self.request_chan.clone(),
             response_map: self.response_map.clone(),
             runtime_manager: self.runtime_manager.clone(),
+            config: self.config.clone(),
         }
     }
 }
@@ -43,7 +54,7 @@ impl Clone for AppState<RuntimeProcessGenerator> {
 pub async fn rambda<G: RuntimeGenerator>(
     State(state): State<AppState<G>>,
     Json(event): Json<RequestEvent>,
-) -> Json<EventResponse> {
+) -> Response {
     debug!("rambda event: {:?}", event);
 
     fn gen_id() -> String {
@@ -55,20 +66,40 @@ pub async fn rambda<G: RuntimeGenerator>(
         state.request_chan,
         state.response_map,
         state.runtime_manager,
+        state.config,
         gen_id,
     )
     .await;
-    Json(response)
+    match response {
+        Ok(InvocationOutcome::Buffered(event_response)) => Json(event_response).into_response(),
+        // streamingモードではチャンクをそのままチャンク化HTTPボディで流す
+        Ok(InvocationOutcome::Streaming(rx)) => Body::new(ChunkBody::new(rx)).into_response(),
+        // ハンドラが失敗した場合は、実際のLambdaと同じく
+        // X-Amz-Function-Errorヘッダを付けてエラー内容を返す
+        Err(error_response) => {
+            let mut header = HeaderMap::new();
+            header.insert("X-Amz-Function-Error", "Unhandled".parse().unwrap());
+            (header, Json(error_response)).into_response()
+        }
+    }
 }
 
 pub async fn invocation_next<G: RuntimeGenerator>(
     State(state): State<AppState<G>>,
+    Path(runtime_id): Path<String>,
 ) -> (HeaderMap, Json<InvocationNextResponse>) {
-    debug!("invocation_next");
+    debug!("invocation_next from runtime {}", runtime_id);
 
-    let response = invocation_next_handler(state.request_chan).await.unwrap();
+    let response = invocation_next_handler(
+        state.request_chan,
+        state.runtime_manager,
+        crate::RuntimeId(runtime_id),
+    )
+    .await
+    .unwrap();
     let aws_request_id = response.0;
     let request_event = response.1;
+    let deadline_ms = response.2;
 
     let mut header = HeaderMap::new();
     header.insert(
@@ -78,11 +109,15 @@ pub async fn invocation_next<G: RuntimeGenerator>(
     header.insert("Lambda-Runtime-Trace-Id", "trace-id".parse().unwrap());
     header.insert(
         "Lambda-Runtime-Invoked-Function-Arn",
-        "arn:aws:lambda:us-east-1:123456789012:function:my-function"
-            .parse()
-            .unwrap(),
+        state.config.arn.parse().unwrap(),
+    );
+    // rambda_handlerが受付時に計算した締め切りをそのまま転記する。ここで改めて
+    // "now + timeout"を計算すると、pool飽和でキューに積まれていた分だけ
+    // dispatch側のtimeoutより長い締め切りを渡してしまう。
+    header.insert(
+        "Lambda-Runtime-Deadline-Ms",
+        deadline_ms.to_string().parse().unwrap(),
     );
-    header.insert("Lambda-Runtime-Deadline-Ms", "3000".parse().unwrap());
 
     (
         header,
@@ -92,17 +127,128 @@ pub async fn invocation_next<G: RuntimeGenerator>(
 
 pub async fn invocation_response<G: RuntimeGenerator>(
     State(state): State<AppState<G>>,
-    Path(aws_request_id): Path<String>,
-    Json(event_response): Json<Option<EventResponse>>,
+    Path((runtime_id, aws_request_id)): Path<(String, String)>,
+    headers: HeaderMap,
+    body: Body,
+) -> (StatusCode, Json<InvocationResponse>) {
+    debug!(
+        "invocation response runtime={} aws_request_id={}",
+        runtime_id, aws_request_id
+    );
+    let runtime_id = crate::RuntimeId(runtime_id);
+    let aws_request_id = crate::AWSRequestId(aws_request_id);
+
+    let streaming = headers
+        .get("Lambda-Runtime-Function-Response-Mode")
+        .and_then(|v| v.to_str().ok())
+        == Some("streaming");
+    if streaming {
+        return invocation_stream_response(
+            state.runtime_manager,
+            state.response_map,
+            runtime_id,
+            aws_request_id,
+            body,
+        )
+        .await;
+    }
+
+    // バッファリングモード(既定): ボディを読み切ってEventResponseとして扱う
+    let event_response = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) if bytes.is_empty() => EventResponse(serde_json::from_str("{}").unwrap()),
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .unwrap_or_else(|_| EventResponse(serde_json::from_str("{}").unwrap())),
+        Err(_) => EventResponse(serde_json::from_str("{}").unwrap()),
+    };
+    let send_result =
+        invocation_response_handler(state.response_map, aws_request_id.clone(), event_response)
+            .await;
+    // バッファリングモードはレスポンス送出をもって処理完了。runtimeをidleに戻す。
+    state.runtime_manager.release(&aws_request_id).await;
+    match send_result {
+        Ok(_) => (
+            StatusCode::ACCEPTED,
+            Json(InvocationResponse::Status(StatusResponse {
+                status: "OK".to_string(),
+            })),
+        ),
+        Err(s) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(InvocationResponse::Error(ErrorResponse {
+                error_message: s,
+                error_type: "NoResponse".to_string(),
+            })),
+        ),
+    }
+}
+
+// streamingモードのボディを読み取り、着信したチャンクをそのまま
+// rambda呼び出し元へ転送する。runtimeのPOSTはボディ終端まで開いたままになる。
+async fn invocation_stream_response<G: RuntimeGenerator>(
+    runtime_manager: RuntimeManager<G>,
+    response_map: ResponseMap,
+    runtime_id: crate::RuntimeId,
+    aws_request_id: crate::AWSRequestId,
+    body: Body,
+) -> (StatusCode, Json<InvocationResponse>) {
+    use http_body_util::BodyExt;
+    debug!("streaming response from runtime {}", runtime_id.0);
+    let stream = match response_map.start_stream(aws_request_id.clone()).await {
+        Some(stream) => stream,
+        None => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(InvocationResponse::Error(ErrorResponse {
+                    error_message: "Sender already taken".to_string(),
+                    error_type: "NoResponse".to_string(),
+                })),
+            );
+        }
+    };
+    let mut body = body;
+    while let Some(frame) = body.frame().await {
+        match frame {
+            Ok(frame) => {
+                if let Ok(data) = frame.into_data()
+                    && stream.send_response_chunk(data).await.is_err()
+                {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    // ストリーム終端まで転送し切ってから、runtimeをidleに戻す。
+    // これより前にreleaseするとストリーミング中のruntimeに別リクエストが割り当たってしまう。
+    stream.finish();
+    runtime_manager.release(&aws_request_id).await;
+    (
+        StatusCode::ACCEPTED,
+        Json(InvocationResponse::Status(StatusResponse {
+            status: "OK".to_string(),
+        })),
+    )
+}
+
+pub async fn invocation_error<G: RuntimeGenerator>(
+    State(state): State<AppState<G>>,
+    Path((runtime_id, aws_request_id)): Path<(String, String)>,
+    Json(error_request): Json<EventErrorRequest>,
 ) -> (StatusCode, Json<InvocationResponse>) {
-    debug!("invocation response aws_request_id: {}", aws_request_id);
-    let aws_request_id = crate::AWSRequestId(aws_request_id.clone());
-    let send_result = invocation_response_handler(
+    debug!(
+        "invocation error runtime={} aws_request_id={}",
+        runtime_id, aws_request_id
+    );
+    let _ = crate::RuntimeId(runtime_id);
+    let aws_request_id = crate::AWSRequestId(aws_request_id);
+    let send_result = invocation_error_handler(
         state.response_map,
-        aws_request_id,
-        event_response.unwrap_or(EventResponse(serde_json::from_str("{}").unwrap())),
+        aws_request_id.clone(),
+        error_request.into(),
     )
     .await;
+    // 失敗応答をもって処理完了。runtimeをidleに戻す。
+    state.runtime_manager.release(&aws_request_id).await;
     match send_result {
         Ok(_) => (
             StatusCode::ACCEPTED,
@@ -120,14 +266,31 @@ pub async fn invocation_response<G: RuntimeGenerator>(
     }
 }
 
-// TODO
-pub async fn invocation_error(
-    Path(aws_request_id): Path<String>,
-    _body: Body,
-) -> Json<InvocationResponse> {
-    debug!("aws_request_id: {}", aws_request_id);
+// プール全体と各runtimeの状態を観測するための読み取り専用エンドポイント。
+// コールドスタートや並行度、リサイクルの様子を開発中に確認できる。
+pub async fn status<G: RuntimeGenerator>(
+    State(state): State<AppState<G>>,
+) -> Json<PoolStatus> {
+    Json(state.runtime_manager.status().await)
+}
 
-    Json(InvocationResponse::Status(StatusResponse {
-        status: "OK".to_string(),
-    }))
+// 初期化中に失敗したruntimeが自身を健全でないと報告してくるエンドポイント。
+// 報告を受けてRuntimeManagerが該当プロセスを破棄し作り直す。
+pub async fn init_error<G: RuntimeGenerator>(
+    State(mut state): State<AppState<G>>,
+    Path(runtime_id): Path<String>,
+    Json(error_request): Json<EventErrorRequest>,
+) -> (StatusCode, Json<InvocationResponse>) {
+    debug!("init error runtime={}: {:?}", runtime_id, error_request);
+    // 初期化に失敗した当のruntimeだけを破棄して作り直す
+    state
+        .runtime_manager
+        .recycle_runtime(&crate::RuntimeId(runtime_id))
+        .await;
+    (
+        StatusCode::ACCEPTED,
+        Json(InvocationResponse::Status(StatusResponse {
+            status: "OK".to_string(),
+        })),
+    )
 }
@@ -3,8 +3,11 @@ use axum::{
     routing::{get, post},
 };
 use rambda::{
-    RuntimeManager, RuntimeProcessGenerator,
-    api::{AppState, invocation_next, invocation_response, rambda},
+    RuntimeGenerator, RuntimeManager, RuntimeProcessGenerator,
+    api::{
+        AppState, init_error, invocation_error, invocation_next, invocation_response, rambda,
+        status,
+    },
 };
 
 #[tokio::main]
@@ -15,29 +18,60 @@ async fn main() {
     let cmd = args.get(1).cloned().unwrap_or("./main".to_string());
     let args = args.get(2..).unwrap_or(&[]).to_vec();
 
-    // runtimeの寿命を10秒に設定
-    let mut manager =
-        RuntimeManager::new(RuntimeProcessGenerator::new(cmd.to_string(), args), 10000);
+    // 同時実行数。環境変数で上書き可能(既定10)。
+    let max_concurrency = std::env::var("RAMBDA_MAX_CONCURRENCY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(10);
 
-    // 最初に一つのプロセスを生成しておく
+    // SIGTERMからSIGKILLへ切り替えるまでの猶予(ミリ秒)。環境変数で上書き可能(既定2000)。
+    let shutdown_grace_ms = std::env::var("RAMBDA_SHUTDOWN_GRACE_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(2000);
+
+    let generator = RuntimeProcessGenerator::with_grace_period(cmd, args, shutdown_grace_ms);
+    serve(RuntimeManager::new(generator, 10000, max_concurrency)).await;
+}
+
+// バックエンドに依らず共通のHTTP表面とライフサイクル管理を起動する
+async fn serve<G>(mut manager: RuntimeManager<G>)
+where
+    G: RuntimeGenerator + Send + Sync + 'static,
+{
+    // 最初に一つのruntimeを生成しておく
     manager.init().await.unwrap();
     let app = Router::new()
-        .route("/2018-06-01/runtime/invocation/next", get(invocation_next))
+        .route(
+            "/runtimes/{runtime_id}/2018-06-01/runtime/invocation/next",
+            get(invocation_next),
+        )
         .route("/", post(rambda))
         .route(
-            "/2018-06-01/runtime/invocation/{aws_request_id}/response",
+            "/runtimes/{runtime_id}/2018-06-01/runtime/invocation/{aws_request_id}/response",
             post(invocation_response),
         )
         .route(
-            "/2018-06-01/runtime/invocation/{aws_request_id}/error",
-            post(invocation_response),
+            "/runtimes/{runtime_id}/2018-06-01/runtime/invocation/{aws_request_id}/error",
+            post(invocation_error),
+        )
+        .route(
+            "/runtimes/{runtime_id}/2018-06-01/runtime/init/error",
+            post(init_error),
         )
-        .with_state(AppState::<RuntimeProcessGenerator>::new(manager.clone()));
+        .route("/status", get(status))
+        .with_state(AppState::with_config(
+            manager.clone(),
+            rambda::config::Config::from_env(),
+        ));
     let listener = tokio::net::TcpListener::bind("localhost:9001")
         .await
         .unwrap();
 
-    // 1秒ごとに期限切れのプロセスを削除する
+    // シャットダウン後に後片付けするため、別クローンを確保しておく
+    let mut shutdown_manager = manager.clone();
+
+    // 1秒ごとに期限切れのruntimeを削除する
     tokio::spawn(async move {
         loop {
             manager.gc().await;
@@ -45,5 +79,18 @@ async fn main() {
         }
     });
 
-    axum::serve(listener, app).await.unwrap();
+    // Ctrl-Cを受けたら新規接続の受付を止め、処理中の呼び出しの完了を待つ
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap();
+
+    // in-flightが捌けた後に各runtimeをSIGTERM→SIGKILLで停止し、ゾンビ化を防ぐ
+    shutdown_manager.shutdown().await;
+}
+
+async fn shutdown_signal() {
+    tokio::signal::ctrl_c()
+        .await
+        .expect("failed to install Ctrl-C handler");
 }
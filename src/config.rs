@@ -0,0 +1,67 @@
+use std::env;
+
+// 関数単位の設定。実際のLambdaと同じく環境変数から読み込み、
+// 未設定の場合はローカル実行向けの既定値を使う。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub function_name: String,
+    pub memory_size_mb: u64,
+    pub timeout_ms: u64,
+    pub region: String,
+    pub arn: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let region = "us-east-1".to_string();
+        let function_name = "my-function".to_string();
+        let arn = format!(
+            "arn:aws:lambda:{}:123456789012:function:{}",
+            region, function_name
+        );
+        Self {
+            function_name,
+            memory_size_mb: 128,
+            timeout_ms: 3000,
+            region,
+            arn,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let default = Config::default();
+        let region =
+            env::var("AWS_REGION").unwrap_or_else(|_| default.region.clone());
+        let function_name = env::var("AWS_LAMBDA_FUNCTION_NAME")
+            .unwrap_or_else(|_| default.function_name.clone());
+        let memory_size_mb = env::var("AWS_LAMBDA_FUNCTION_MEMORY_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default.memory_size_mb);
+        let timeout_ms = env::var("AWS_LAMBDA_FUNCTION_TIMEOUT_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default.timeout_ms);
+        let arn = env::var("AWS_LAMBDA_FUNCTION_ARN").unwrap_or_else(|_| {
+            format!(
+                "arn:aws:lambda:{}:123456789012:function:{}",
+                region, function_name
+            )
+        });
+        Self {
+            function_name,
+            memory_size_mb,
+            timeout_ms,
+            region,
+            arn,
+        }
+    }
+
+    // リクエストを受け付けた時点(エポックミリ秒)を起点に、このリクエストの締め切りを計算する。
+    // dispatch側とtimeout側で同じ起点を使うよう、呼び出し側は一度計算した値を使い回すこと。
+    pub fn deadline_at(&self, accepted_at_ms: u64) -> u64 {
+        accepted_at_ms + self.timeout_ms
+    }
+}
@@ -1,13 +1,26 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+};
 
+use bytes::Bytes;
+use http_body::{Body as HttpBody, Frame};
 use log::debug;
 use tokio::{
-    process::Command,
+    process::{Child, Command},
     sync::{Mutex, mpsc, oneshot},
 };
-use types::{EventResponse, RequestEvent};
+use config::Config;
+use types::{ErrorResponse, EventResponse, PoolStatus, RequestEvent, RuntimeStatus};
 
 pub mod api;
+pub mod config;
 pub mod types;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -18,37 +31,83 @@ pub async fn rambda_handler<G: RuntimeGenerator, I: Fn() -> String>(
     request_chan: RequestChannel,
     response_map: ResponseMap,
     mut runtime_manager: RuntimeManager<G>,
+    config: Config,
     gen_id: I,
-) -> EventResponse {
+) -> Result<InvocationOutcome, ErrorResponse> {
     let aws_request_id = AWSRequestId(gen_id());
 
-    // runtime側にリクエストを送信
-    while let Err(SendRequestEventToChannelError::FailedToSend) = request_chan
-        .send_request(&aws_request_id, request_event.clone())
-        .await
-    {
-        debug!("request channel is full, waiting for runtime to process");
-        // runtimeが処理中の場合は、新しいruntimeを生成しておく
-        runtime_manager.init().await.unwrap();
-        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-    }
+    // 受付時点を起点に締め切りを一度だけ計算し、/next へ渡すヘッダと
+    // このあとのtimeoutの両方で同じ値を使う。別々に計算すると、
+    // pool飽和でキューに積まれている間に両者の時計がずれてしまう。
+    let accepted_at_ms = chrono::Utc::now().timestamp_millis() as u64;
+    let deadline_ms = config.deadline_at(accepted_at_ms);
 
-    debug!("request channel sent");
+    // idleなruntimeが無ければ上限まで増やしてから投入する。
+    // 上限に達している場合は、キューのバックプレッシャで待たされる。
+    runtime_manager.ensure_capacity().await;
     response_map.add_new_request(aws_request_id.clone()).await;
+    request_chan
+        .send_request(&aws_request_id, request_event, deadline_ms)
+        .await
+        .expect("request channel closed");
+    debug!("request channel sent");
 
-    // runtime側からのレスポンスを待つ
+    // runtime側からのレスポンスを、締め切りまで待つ。ensure_capacity/send_requestで
+    // 既に消費した時間を差し引かないと、キューで待たされた分だけ締め切りを超過して待ってしまう。
     debug!("waiting for runtime response");
-    let response = response_map.get_response(&aws_request_id).await.unwrap();
-    debug!("response: {:?}", response);
-    response
+    let now_after_enqueue = chrono::Utc::now().timestamp_millis() as u64;
+    let remaining = deadline_ms.saturating_sub(now_after_enqueue);
+    let deadline = tokio::time::Duration::from_millis(remaining);
+    match tokio::time::timeout(deadline, response_map.get_response(&aws_request_id)).await {
+        Ok(response) => {
+            let response = response.unwrap();
+            debug!("response: {:?}", response);
+            // releaseはレスポンス送出側(/response・/error ハンドラ)で行う。
+            // streamingモードではここで返すのは受信側ハンドルだけで、
+            // 本体はまだ流れている途中のため、ここでreleaseしてはならない。
+            response
+        }
+        Err(_) => {
+            // 締め切りを超過したruntimeは詰まっている可能性があるため破棄して作り直す
+            debug!("invocation timed out: {:?}", aws_request_id);
+            response_map.cancel_request(&aws_request_id).await;
+            runtime_manager.recycle_request(&aws_request_id).await;
+            Err(ErrorResponse {
+                error_message: format!(
+                    "{} Task timed out after {:.2} seconds",
+                    config.function_name,
+                    config.timeout_ms as f64 / 1000.0
+                ),
+                error_type: "Sandbox.Timedout".to_string(),
+            })
+        }
+    }
 }
 
-pub async fn invocation_next_handler(
+pub async fn invocation_error_handler(
+    map: ResponseMap,
+    aws_request_id: AWSRequestId,
+    error_response: ErrorResponse,
+) -> Result<(), String> {
+    // rambda側にハンドラの失敗を通知する
+    map.send_error(aws_request_id, error_response)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+pub async fn invocation_next_handler<G: RuntimeGenerator>(
     mut chan: RequestChannel,
-) -> Option<(AWSRequestId, RequestEvent)> {
-    // runtime側にリクエストを返信
+    runtime_manager: RuntimeManager<G>,
+    runtime_id: RuntimeId,
+) -> Option<(AWSRequestId, RequestEvent, u64)> {
+    // long-poll: キューから一件取り出し、pollしてきた当の runtime_id をbusyとして占有する。
+    // /next のURLに runtime_id が含まれるため、どのruntimeが処理中かを正確に追跡できる。
     match chan.recv_request().await {
-        Some(request_event) => Some(request_event),
+        Some((aws_request_id, request_event, deadline_ms)) => {
+            runtime_manager.claim(&runtime_id, &aws_request_id).await;
+            Some((aws_request_id, request_event, deadline_ms))
+        }
         None => panic!("request channel closed, removing request"),
     }
 }
@@ -65,11 +124,15 @@ pub async fn invocation_response_handler(
     Ok(())
 }
 
+// キューで運ぶのはイベント本体に加え、受付時点で計算した締め切り(エポックミリ秒)。
+// /next がこれをそのままLambda-Runtime-Deadline-Msへ転記することで、
+// dispatch側のtimeoutと同じ時計を指すようにする。
 pub struct RequestChannel {
-    tx: mpsc::Sender<(AWSRequestId, RequestEvent)>,
-    rx: Arc<Mutex<mpsc::Receiver<(AWSRequestId, RequestEvent)>>>,
+    tx: mpsc::Sender<(AWSRequestId, RequestEvent, u64)>,
+    rx: Arc<Mutex<mpsc::Receiver<(AWSRequestId, RequestEvent, u64)>>>,
 }
 
+#[derive(Debug)]
 pub enum SendRequestEventToChannelError {
     FailedToSend,
     SenderAlreadyTaken,
@@ -78,30 +141,33 @@ pub enum SendRequestEventToChannelError {
 
 impl Default for RequestChannel {
     fn default() -> Self {
-        let (tx, rx) = mpsc::channel(1);
+        RequestChannel::new(1)
+    }
+}
+
+impl RequestChannel {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, rx) = mpsc::channel(capacity);
         RequestChannel {
             tx,
             rx: Arc::new(Mutex::new(rx)),
         }
     }
-}
 
-impl RequestChannel {
     pub async fn send_request(
         &self,
         aws_request_id: &AWSRequestId,
         resp: RequestEvent,
+        deadline_ms: u64,
     ) -> Result<(), SendRequestEventToChannelError> {
-        match self.tx.try_send((aws_request_id.clone(), resp)) {
-            Ok(_) => Ok(()),
-            Err(_) => {
-                // bufferが1なので、受信側が受信していない場合はErrになる
-                Err(SendRequestEventToChannelError::FailedToSend)
-            }
-        }
+        // キューが一杯なら空きが出るまで待つ(バックプレッシャ)
+        self.tx
+            .send((aws_request_id.clone(), resp, deadline_ms))
+            .await
+            .map_err(|_| SendRequestEventToChannelError::FailedToSend)
     }
 
-    pub async fn recv_request(&mut self) -> Option<(AWSRequestId, RequestEvent)> {
+    pub async fn recv_request(&mut self) -> Option<(AWSRequestId, RequestEvent, u64)> {
         self.rx.lock().await.recv().await
     }
 }
@@ -114,9 +180,59 @@ impl Clone for RequestChannel {
     }
 }
 
+// ハンドラからの出力。既定はバッファリングされた単一のレスポンス、
+// Lambda-Runtime-Function-Response-Mode: streaming の場合はチャンク列になる。
+#[derive(Debug)]
+pub enum InvocationOutcome {
+    Buffered(EventResponse),
+    Streaming(mpsc::Receiver<Bytes>),
+}
+
+type ResponsePayload = Result<InvocationOutcome, ErrorResponse>;
+
+// streamingモードのレスポンスボディをrambda呼び出し元へ返すためのhttp_body実装。
+// mpscで受け取ったBytesを順にフレームとして流す。
+pub struct ChunkBody {
+    rx: mpsc::Receiver<Bytes>,
+}
+impl ChunkBody {
+    pub fn new(rx: mpsc::Receiver<Bytes>) -> Self {
+        Self { rx }
+    }
+}
+impl HttpBody for ChunkBody {
+    type Data = Bytes;
+    type Error = Infallible;
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match self.rx.poll_recv(cx) {
+            Poll::Ready(Some(bytes)) => Poll::Ready(Some(Ok(Frame::data(bytes)))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// streamingモードでチャンクを送出するための書き込み側ハンドル。
+pub struct ResponseStream {
+    tx: mpsc::Sender<Bytes>,
+}
+impl ResponseStream {
+    pub async fn send_response_chunk(&self, chunk: Bytes) -> Result<(), String> {
+        self.tx
+            .send(chunk)
+            .await
+            .map_err(|_| "response stream closed".to_string())
+    }
+    // 送信側を落とすことでボディの終端を伝える
+    pub fn finish(self) {}
+}
+
 pub struct ResponseMap {
-    r_map: Arc<Mutex<HashMap<AWSRequestId, oneshot::Receiver<EventResponse>>>>,
-    t_map: Arc<Mutex<HashMap<AWSRequestId, oneshot::Sender<EventResponse>>>>,
+    r_map: Arc<Mutex<HashMap<AWSRequestId, oneshot::Receiver<ResponsePayload>>>>,
+    t_map: Arc<Mutex<HashMap<AWSRequestId, oneshot::Sender<ResponsePayload>>>>,
 }
 impl Clone for ResponseMap {
     fn clone(&self) -> Self {
@@ -141,7 +257,10 @@ impl ResponseMap {
         self.r_map.lock().await.insert(aws_request_id.clone(), rx);
         self.t_map.lock().await.insert(aws_request_id, tx);
     }
-    pub async fn get_response(&self, aws_request_id: &AWSRequestId) -> Option<EventResponse> {
+    pub async fn get_response(
+        &self,
+        aws_request_id: &AWSRequestId,
+    ) -> Option<ResponsePayload> {
         let rx = self.r_map.lock().await.remove(aws_request_id);
 
         if let Some(rx) = rx {
@@ -154,10 +273,38 @@ impl ResponseMap {
         &self,
         aws_request_id: AWSRequestId,
         response: EventResponse,
+    ) -> Result<(), String> {
+        self.send_payload(aws_request_id, Ok(InvocationOutcome::Buffered(response)))
+            .await
+    }
+    // streamingモードを開始し、呼び出し元へ受信側を渡して書き込み用ハンドルを返す
+    pub async fn start_stream(&self, aws_request_id: AWSRequestId) -> Option<ResponseStream> {
+        let (tx, rx) = mpsc::channel(32);
+        self.send_payload(aws_request_id, Ok(InvocationOutcome::Streaming(rx)))
+            .await
+            .ok()?;
+        Some(ResponseStream { tx })
+    }
+    // タイムアウト等で待受を打ち切る際に、残っているチャネルを取り除く
+    pub async fn cancel_request(&self, aws_request_id: &AWSRequestId) {
+        self.r_map.lock().await.remove(aws_request_id);
+        self.t_map.lock().await.remove(aws_request_id);
+    }
+    pub async fn send_error(
+        &self,
+        aws_request_id: AWSRequestId,
+        error: ErrorResponse,
+    ) -> Result<(), String> {
+        self.send_payload(aws_request_id, Err(error)).await
+    }
+    async fn send_payload(
+        &self,
+        aws_request_id: AWSRequestId,
+        payload: ResponsePayload,
     ) -> Result<(), String> {
         let tx = self.t_map.lock().await.remove(&aws_request_id);
         if let Some(tx) = tx {
-            tx.send(response)
+            tx.send(payload)
                 .map_err(|_| "Failed to send response".to_string())
         } else {
             Err("Sender already taken".to_string())
@@ -169,6 +316,7 @@ pub struct RuntimeManager<G: RuntimeGenerator> {
     generator: G,
     runtime_list: Arc<Mutex<RuntimeList>>,
     lifetime_ms: u64,
+    max_concurrency: usize,
 }
 impl<G: RuntimeGenerator> Clone for RuntimeManager<G> {
     fn clone(&self) -> Self {
@@ -176,6 +324,7 @@ impl<G: RuntimeGenerator> Clone for RuntimeManager<G> {
             generator: self.generator.clone(),
             runtime_list: self.runtime_list.clone(),
             lifetime_ms: self.lifetime_ms,
+            max_concurrency: self.max_concurrency,
         }
     }
 }
@@ -183,13 +332,32 @@ impl<G: RuntimeGenerator> Clone for RuntimeManager<G> {
 pub struct RuntimeProcessGenerator {
     cmd: String,
     args: Vec<String>,
+    // ランタイムAPIのauthority。各runtimeには /runtimes/<id> を足した固有のベースを渡す。
+    api_base: String,
+    // SIGTERMを送ってからSIGKILLに切り替えるまでの猶予(ミリ秒)
+    grace_period_ms: u64,
+    // 自前で採番するRuntimeId。pollしてきたruntimeをURLで識別できるようにする。
+    counter: Arc<AtomicU64>,
+    // RuntimeIdから子プロセスを引けるようにして、終了シグナルと刈り取りを行う。
+    children: Arc<Mutex<HashMap<RuntimeId, Child>>>,
 }
 
 impl RuntimeProcessGenerator {
     pub fn new(cmd: impl Into<String>, args: Vec<impl Into<String>>) -> Self {
+        Self::with_grace_period(cmd, args, 2000)
+    }
+    pub fn with_grace_period(
+        cmd: impl Into<String>,
+        args: Vec<impl Into<String>>,
+        grace_period_ms: u64,
+    ) -> Self {
         Self {
             cmd: cmd.into(),
             args: args.into_iter().map(Into::into).collect(),
+            api_base: "localhost:9001".to_string(),
+            grace_period_ms,
+            counter: Arc::new(AtomicU64::new(0)),
+            children: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 }
@@ -197,26 +365,63 @@ impl RuntimeProcessGenerator {
 impl RuntimeGenerator for RuntimeProcessGenerator {
     async fn init(&self) -> Result<Runtime, String> {
         let start_time = chrono::Utc::now().timestamp_millis() as u64;
+        // pidではなく自前のidを採番し、そのidを含むランタイムAPIベースを子に渡す。
+        // これにより /runtimes/<id>/.../invocation/next へpollしたruntimeを識別できる。
+        let runtime_id = RuntimeId(format!("rt-{}", self.counter.fetch_add(1, Ordering::SeqCst)));
+        let api = format!("{}/runtimes/{}", self.api_base, runtime_id.0);
 
         let child = Command::new(self.cmd.as_str())
             .args(&self.args)
-            .env("AWS_LAMBDA_RUNTIME_API", "localhost:9001")
+            .env("AWS_LAMBDA_RUNTIME_API", api)
             .spawn()
             .map_err(|e| format!("Failed to spawn process: {}", e))?;
-        let runtime = Runtime::new(RuntimeId(child.id().unwrap().to_string()), start_time);
-        debug!("spawned process: {:?}", runtime.id);
+        self.children
+            .lock()
+            .await
+            .insert(runtime_id.clone(), child);
+        debug!("spawned process: {:?}", runtime_id);
 
-        Ok(runtime)
+        Ok(Runtime::new(runtime_id, start_time))
     }
 
     async fn kill(&self, runtime_id: &RuntimeId) -> Result<(), String> {
-        Command::new("kill")
-            .args(["-9", &runtime_id.0])
+        let Some(mut child) = self.children.lock().await.remove(runtime_id) else {
+            return Ok(());
+        };
+        let Some(pid) = child.id() else {
+            return Ok(());
+        };
+        let pid = pid.to_string();
+        // まずSIGTERMで穏やかに停止を促す
+        let _ = Command::new("kill")
+            .args(["-TERM", &pid])
             .spawn()
-            .map_err(|e| format!("Failed to kill process: {}", e))?
+            .map_err(|e| format!("Failed to signal process: {}", e))?
             .wait()
-            .await
-            .map_err(|e| format!("Failed to wait for process: {}", e))?;
+            .await;
+        // 猶予の間は終了したかを小刻みにポーリングし、自分から抜けたら即座に刈り取る。
+        // 猶予いっぱいまで待ってしまうと、行儀よく終了したプロセスでも無駄に待たされる。
+        let deadline = tokio::time::Duration::from_millis(self.grace_period_ms);
+        let poll_interval = tokio::time::Duration::from_millis(50);
+        let start = tokio::time::Instant::now();
+        let exited = loop {
+            match child.try_wait() {
+                Ok(Some(_)) => break true,
+                Ok(None) => {
+                    if start.elapsed() >= deadline {
+                        break false;
+                    }
+                    tokio::time::sleep(poll_interval).await;
+                }
+                Err(_) => break false,
+            }
+        };
+        // 猶予後も残っていればSIGKILLで強制終了する
+        if !exited {
+            let _ = child.kill().await;
+            // ゾンビ化を避けるため終了ステータスを刈り取る
+            let _ = child.wait().await;
+        }
         debug!("killed process: {:?}", runtime_id);
         Ok(())
     }
@@ -225,66 +430,342 @@ impl RuntimeGenerator for RuntimeProcessGenerator {
         Self {
             cmd: self.cmd.clone(),
             args: self.args.clone(),
+            api_base: self.api_base.clone(),
+            grace_period_ms: self.grace_period_ms,
+            counter: self.counter.clone(),
+            children: self.children.clone(),
         }
     }
 }
 
 impl<G: RuntimeGenerator> RuntimeManager<G> {
-    pub fn new(generator: G, lifetime_ms: u64) -> Self {
+    pub fn new(generator: G, lifetime_ms: u64, max_concurrency: usize) -> Self {
         Self {
             generator,
             runtime_list: Arc::new(Mutex::new(RuntimeList::new())),
             lifetime_ms,
+            max_concurrency,
         }
     }
     pub async fn gc(&mut self) {
-        let expires = self.runtime_list.lock().await.0.clone();
+        let expires = self.runtime_list.lock().await.runtimes.clone();
         let lifetime = self.lifetime_ms;
         let expires = expires
             .iter()
+            // 処理中(busy)のruntimeは巻き込まないよう、idleなものだけ回収する
             .filter(|r| r.start + lifetime < chrono::Utc::now().timestamp_millis() as u64);
         for runtime in expires {
-            self.kill(&runtime.id).await;
+            let idle = self.runtime_list.lock().await.is_idle(&runtime.id);
+            if idle {
+                // 寿命切れのidle runtimeはExpiredに落としてから回収する
+                self.runtime_list
+                    .lock()
+                    .await
+                    .transition(&runtime.id, RuntimeState::Expired);
+                self.kill(&runtime.id).await;
+            }
         }
         // もしruntimeが一つもなければ、再度生成する
-        if self.runtime_list.lock().await.0.is_empty() {
+        if self.runtime_list.lock().await.len() == 0 {
             self.init().await.unwrap();
         }
-        debug!("process num: {:?}", self.runtime_list.lock().await.0.len());
+        debug!("process num: {:?}", self.runtime_list.lock().await.len());
+    }
+
+    // idleなruntimeが無く、かつ上限に達していなければ一つ増やす(スケールアップ)。
+    // チェックと「増やす権利」の確保は同じロック区間で行う。そうしないと、
+    // 複数の呼び出しが同時に len < max_concurrency を観測してから
+    // それぞれ generator.init() を実行し、上限を超えてしまう。
+    pub async fn ensure_capacity(&mut self) {
+        let should_init = {
+            let mut list = self.runtime_list.lock().await;
+            let capacity_used = list.len() + list.reserved();
+            if !list.has_idle() && capacity_used < self.max_concurrency {
+                list.reserve();
+                true
+            } else {
+                false
+            }
+        };
+        if !should_init {
+            return;
+        }
+        debug!("scaling up runtime pool");
+        let result = self.init().await;
+        self.runtime_list.lock().await.unreserve();
+        result.unwrap();
+    }
+
+    pub fn max_concurrency(&self) -> usize {
+        self.max_concurrency
+    }
+
+    // pollしてきた特定のruntimeを、与えられたリクエストで占有する
+    pub async fn claim(&self, runtime_id: &RuntimeId, aws_request_id: &AWSRequestId) {
+        self.runtime_list
+            .lock()
+            .await
+            .claim(runtime_id, aws_request_id);
+    }
+
+    pub async fn release(&self, aws_request_id: &AWSRequestId) {
+        self.runtime_list.lock().await.release(aws_request_id);
+    }
+
+    // 締め切り超過などで詰まったruntimeを、そのリクエストidから特定して破棄・再生成する
+    pub async fn recycle_request(&mut self, aws_request_id: &AWSRequestId) {
+        let runtime_id = self.runtime_list.lock().await.find_by_request(aws_request_id);
+        match runtime_id {
+            Some(id) => {
+                self.kill(&id).await;
+                self.init().await.unwrap();
+            }
+            // 担当runtimeがまだ割り当てられていない(pool飽和でキューに積まれたまま
+            // 締め切りが来た等)場合、他の健全なruntimeを巻き込みたくないので何もしない。
+            None => debug!(
+                "recycle_request: no runtime owned {:?}, leaving pool untouched",
+                aws_request_id
+            ),
+        }
     }
 
     pub async fn init(&mut self) -> Result<Runtime, String> {
         let runtime = self.generator.init().await?;
-        self.runtime_list.lock().await.add(runtime.clone());
+        let mut list = self.runtime_list.lock().await;
+        list.add(runtime.clone());
+        // 生成に成功したのでIdleに遷移させ、受付可能にする
+        list.mark_ready(&runtime.id);
         Ok(runtime)
     }
 
+    pub async fn status(&self) -> PoolStatus {
+        self.runtime_list.lock().await.status()
+    }
+
     async fn kill(&mut self, runtime_id: &RuntimeId) {
         self.generator.kill(runtime_id).await.unwrap();
         self.runtime_list.lock().await.remove(runtime_id);
     }
+
+    // シャットダウン時に全runtimeを停止する。各プロセスはSIGTERM→SIGKILLの順で終了させる。
+    pub async fn shutdown(&mut self) {
+        let runtimes = self.runtime_list.lock().await.runtimes.clone();
+        debug!("shutting down {} runtime(s)", runtimes.len());
+        for runtime in runtimes {
+            self.kill(&runtime.id).await;
+        }
+    }
+
+    // 特定のruntimeだけをFailedに落として破棄し、一つ作り直す。
+    // 初期化失敗を自己申告してきたruntimeの後始末に使う。
+    pub async fn recycle_runtime(&mut self, runtime_id: &RuntimeId) {
+        let exists = self.runtime_list.lock().await.contains(runtime_id);
+        if !exists {
+            return;
+        }
+        self.runtime_list
+            .lock()
+            .await
+            .transition(runtime_id, RuntimeState::Failed);
+        self.kill(runtime_id).await;
+        self.init().await.unwrap();
+    }
+
+    // 初期化に失敗したruntimeは健全でないため、全て破棄して一つ作り直す
+    pub async fn recycle(&mut self) {
+        let runtimes = self.runtime_list.lock().await.runtimes.clone();
+        for runtime in runtimes {
+            // 健全でないと判断したruntimeはFailedに落としてから破棄する
+            self.runtime_list
+                .lock()
+                .await
+                .transition(&runtime.id, RuntimeState::Failed);
+            self.kill(&runtime.id).await;
+        }
+        self.init().await.unwrap();
+        debug!("recycled runtimes, process num: 1");
+    }
 }
 
+// RuntimeProcessGeneratorが唯一の実装。wasm32-wasi/wasmtimeバックエンドは未実装のまま
+// (CLI/feature flagも存在しない)。一度試みたが、next/responseをプロセス内チャネルで
+// 駆動するには「ホストがrecv/sendする関数をwasmゲストにimportとして公開する」という
+// 新しいABIの設計が要り、WASI標準入出力だけを繋いでも(=その際の実装)ランタイムは
+// 決して/nextをpollできずトラフィックを捌けないため、ABI設計ごと見送った。
+// このトレイトへ追加実装として再挑戦できるが、そのABI契約とfixtureになる.wasmが無い限り着手しない。
 pub trait RuntimeGenerator {
-    fn init(&self) -> impl Future<Output = Result<Runtime, String>>;
-    fn kill(&self, runtime_id: &RuntimeId) -> impl Future<Output = Result<(), String>>;
+    // HTTPハンドラはSendな状態で跨ってawaitされるため、生成/破棄のFutureもSendを要求する
+    fn init(&self) -> impl Future<Output = Result<Runtime, String>> + Send;
+    fn kill(&self, runtime_id: &RuntimeId) -> impl Future<Output = Result<(), String>> + Send;
     fn clone(&self) -> Self;
 }
 
+// runtimeのライフサイクル状態機械。
+// Initializing → Idle → Busy → (Expired|Failed) → Killed の順に遷移する。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuntimeState {
+    Initializing,
+    Idle,
+    Busy(AWSRequestId),
+    Expired,
+    Failed,
+    Killed,
+}
+
+impl RuntimeState {
+    // /statusで公開するための短いラベル
+    pub fn label(&self) -> &'static str {
+        match self {
+            RuntimeState::Initializing => "initializing",
+            RuntimeState::Idle => "idle",
+            RuntimeState::Busy(_) => "busy",
+            RuntimeState::Expired => "expired",
+            RuntimeState::Failed => "failed",
+            RuntimeState::Killed => "killed",
+        }
+    }
+}
+
+// 1つのruntimeについての可観測な状態
 #[derive(Clone)]
-struct RuntimeList(Vec<Runtime>);
+struct RuntimeRecord {
+    state: RuntimeState,
+    invocations: u64,
+}
+
+#[derive(Clone)]
+struct RuntimeList {
+    runtimes: Vec<Runtime>,
+    records: HashMap<RuntimeId, RuntimeRecord>,
+    // ensure_capacityが生成中(まだruntimesに入っていない)に予約した枠の数
+    reserved: usize,
+}
 
 impl RuntimeList {
     fn new() -> Self {
-        Self(Vec::new())
+        Self {
+            runtimes: Vec::new(),
+            records: HashMap::new(),
+            reserved: 0,
+        }
+    }
+
+    fn reserved(&self) -> usize {
+        self.reserved
+    }
+
+    fn reserve(&mut self) {
+        self.reserved += 1;
+    }
+
+    fn unreserve(&mut self) {
+        self.reserved = self.reserved.saturating_sub(1);
+    }
+
+    // 状態遷移を構造化ログとして記録しつつ反映する
+    fn transition(&mut self, runtime_id: &RuntimeId, state: RuntimeState) {
+        if let Some(record) = self.records.get_mut(runtime_id) {
+            debug!(
+                "runtime state: id={} {} -> {}",
+                runtime_id.0,
+                record.state.label(),
+                state.label()
+            );
+            record.state = state;
+        }
     }
 
     fn add(&mut self, runtime: Runtime) {
-        self.0.push(runtime);
+        debug!("runtime state: id={} -> initializing", runtime.id.0);
+        self.records.insert(
+            runtime.id.clone(),
+            RuntimeRecord {
+                state: RuntimeState::Initializing,
+                invocations: 0,
+            },
+        );
+        self.runtimes.push(runtime);
+    }
+
+    // 初期化完了。Idleにして受付可能にする。
+    fn mark_ready(&mut self, runtime_id: &RuntimeId) {
+        self.transition(runtime_id, RuntimeState::Idle);
     }
 
     fn remove(&mut self, runtime_id: &RuntimeId) {
-        self.0.retain(|r| r.id != *runtime_id);
+        self.transition(runtime_id, RuntimeState::Killed);
+        self.runtimes.retain(|r| r.id != *runtime_id);
+        self.records.remove(runtime_id);
+    }
+
+    fn len(&self) -> usize {
+        self.runtimes.len()
+    }
+
+    fn contains(&self, runtime_id: &RuntimeId) -> bool {
+        self.records.contains_key(runtime_id)
+    }
+
+    fn has_idle(&self) -> bool {
+        self.records
+            .values()
+            .any(|r| r.state == RuntimeState::Idle)
+    }
+
+    fn is_idle(&self, runtime_id: &RuntimeId) -> bool {
+        matches!(
+            self.records.get(runtime_id),
+            Some(RuntimeRecord {
+                state: RuntimeState::Idle,
+                ..
+            })
+        )
+    }
+
+    // pollしてきた当の runtime_id を、与えられたリクエストで占有(busy)にする
+    fn claim(&mut self, runtime_id: &RuntimeId, aws_request_id: &AWSRequestId) {
+        match self.records.get_mut(runtime_id) {
+            Some(record) => record.invocations += 1,
+            None => return,
+        }
+        self.transition(runtime_id, RuntimeState::Busy(aws_request_id.clone()));
+    }
+
+    fn find_by_request(&self, aws_request_id: &AWSRequestId) -> Option<RuntimeId> {
+        self.records
+            .iter()
+            .find(|(_, record)| record.state == RuntimeState::Busy(aws_request_id.clone()))
+            .map(|(id, _)| id.clone())
+    }
+
+    fn release(&mut self, aws_request_id: &AWSRequestId) {
+        if let Some(id) = self.find_by_request(aws_request_id) {
+            self.transition(&id, RuntimeState::Idle);
+        }
+    }
+
+    fn status(&self) -> PoolStatus {
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        let runtimes = self
+            .runtimes
+            .iter()
+            .map(|r| {
+                let record = self.records.get(&r.id);
+                RuntimeStatus {
+                    id: r.id.0.clone(),
+                    state: record
+                        .map(|rec| rec.state.label())
+                        .unwrap_or("unknown")
+                        .to_string(),
+                    age_ms: now.saturating_sub(r.start),
+                    invocations: record.map(|rec| rec.invocations).unwrap_or(0),
+                }
+            })
+            .collect();
+        PoolStatus {
+            pool_size: self.runtimes.len(),
+            runtimes,
+        }
     }
 }
 
@@ -326,7 +807,6 @@ mod tests {
         }
     }
     fn new_mock_gen_id(id: String) -> impl Fn() -> String {
-        let id = id;
         move || id.clone()
     }
     #[tokio::test]
@@ -348,16 +828,22 @@ mod tests {
                     initd_runtimes: vec![],
                 },
                 0,
+                10,
             );
             let rambda_handler_result = rambda_handler(
                 request_event.clone(),
                 request_map.clone(),
                 response_map.clone(),
                 manager.clone(),
+                Config::default(),
                 new_mock_gen_id(id.clone()),
             );
             let aws_request_id = AWSRequestId(id.clone());
-            let wait_invocation_next = invocation_next_handler(request_map.clone());
+            let wait_invocation_next = invocation_next_handler(
+                request_map.clone(),
+                manager.clone(),
+                RuntimeId("runtime_0".to_string()),
+            );
             let mut response = Map::new();
             response.insert(
                 "key".to_string(),
@@ -373,9 +859,268 @@ mod tests {
                 wait_invocation_next,
                 wait_invocation_response
             );
-            assert_eq!(rambda_handler_result, EventResponse(response));
-            assert_eq!(wait_invocation_next, Some((aws_request_id, request_event)));
+            match rambda_handler_result {
+                Ok(InvocationOutcome::Buffered(r)) => assert_eq!(r, EventResponse(response)),
+                _ => panic!("expected a buffered response"),
+            }
+            let (next_request_id, next_request_event, _deadline_ms) =
+                wait_invocation_next.expect("expected a queued request");
+            assert_eq!(next_request_id, aws_request_id);
+            assert_eq!(next_request_event, request_event);
             assert_eq!(wait_invocation_response, Ok(()));
         }
     }
+
+    // killされたruntime_idを記録するだけのMock。recycleの巻き込み範囲を検証するのに使う。
+    // init_delay_msを設定すると、生成に時間のかかるバックエンドを模倣できる
+    // (ensure_capacityの競合を再現するのに使う)。
+    struct TrackingRuntimeGenerator {
+        counter: Arc<AtomicU64>,
+        killed: Arc<Mutex<Vec<RuntimeId>>>,
+        init_delay_ms: u64,
+    }
+    impl TrackingRuntimeGenerator {
+        fn new() -> Self {
+            Self {
+                counter: Arc::new(AtomicU64::new(0)),
+                killed: Arc::new(Mutex::new(Vec::new())),
+                init_delay_ms: 0,
+            }
+        }
+        fn with_init_delay(delay_ms: u64) -> Self {
+            Self {
+                init_delay_ms: delay_ms,
+                ..Self::new()
+            }
+        }
+    }
+    impl RuntimeGenerator for TrackingRuntimeGenerator {
+        async fn init(&self) -> Result<Runtime, String> {
+            if self.init_delay_ms > 0 {
+                tokio::time::sleep(tokio::time::Duration::from_millis(self.init_delay_ms)).await;
+            }
+            let id = self.counter.fetch_add(1, Ordering::SeqCst);
+            Ok(Runtime::new(RuntimeId(format!("runtime_{}", id)), 0))
+        }
+        async fn kill(&self, runtime_id: &RuntimeId) -> Result<(), String> {
+            self.killed.lock().await.push(runtime_id.clone());
+            Ok(())
+        }
+        fn clone(&self) -> Self {
+            Self {
+                counter: self.counter.clone(),
+                killed: self.killed.clone(),
+                init_delay_ms: self.init_delay_ms,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn recycle_request_leaves_other_runtimes_alone_when_owner_is_known() {
+        let generator = TrackingRuntimeGenerator::new();
+        let killed = generator.killed.clone();
+        let mut manager = RuntimeManager::new(generator, 10_000, 10);
+        let owner = manager.init().await.unwrap();
+        let bystander = manager.init().await.unwrap();
+
+        let aws_request_id = AWSRequestId("owned".to_string());
+        manager.claim(&owner.id, &aws_request_id).await;
+
+        manager.recycle_request(&aws_request_id).await;
+
+        // 担当していたruntimeだけがkillされ、無関係なruntimeは巻き込まれない
+        assert_eq!(killed.lock().await.as_slice(), [owner.id]);
+        let status = manager.status().await;
+        assert!(status.runtimes.iter().any(|r| r.id == bystander.id.0));
+    }
+
+    #[tokio::test]
+    async fn recycle_request_is_a_noop_when_owner_is_unknown() {
+        let generator = TrackingRuntimeGenerator::new();
+        let killed = generator.killed.clone();
+        let mut manager = RuntimeManager::new(generator, 10_000, 10);
+        manager.init().await.unwrap();
+        manager.init().await.unwrap();
+
+        // どのruntimeにも割り当てられていないリクエストidでの締め切り超過。
+        // pool飽和でキューに積まれたまま締め切りが来たケースに相当する。
+        let aws_request_id = AWSRequestId("never-dispatched".to_string());
+        manager.recycle_request(&aws_request_id).await;
+
+        assert!(killed.lock().await.is_empty());
+        assert_eq!(manager.status().await.pool_size, 2);
+    }
+
+    #[tokio::test]
+    async fn rambda_handler_timeout_accounts_for_time_already_spent_queued() {
+        // キューが埋まっている間に経過した時間を差し引かず、受付時刻からtimeout_msを
+        // 丸ごと足した分だけ待ってしまう回帰を防ぐ。キューを埋めておき、timeout_msより
+        // 長い遅延の後にしか空かないようにして、全体の待ち時間がqueue解放+timeout_msの
+        // 和ではなく、queue解放からtimeout_ms程度で打ち切られることを確認する。
+        let request_chan = RequestChannel::new(1);
+        let filler = AWSRequestId("filler".to_string());
+        request_chan
+            .send_request(&filler, RequestEvent(Map::new()), 0)
+            .await
+            .unwrap();
+
+        let queue_delay_ms = 150;
+        let mut drain_chan = request_chan.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(tokio::time::Duration::from_millis(queue_delay_ms)).await;
+            drain_chan.recv_request().await;
+        });
+
+        let config = Config {
+            timeout_ms: 100,
+            ..Config::default()
+        };
+        let generator = MockRuntimeGenerator {
+            initd_runtimes: Vec::new(),
+        };
+        let manager = RuntimeManager::new(generator, 10_000, 10);
+
+        let start = tokio::time::Instant::now();
+        let result = rambda_handler(
+            RequestEvent(Map::new()),
+            request_chan,
+            ResponseMap::default(),
+            manager,
+            config,
+            new_mock_gen_id("request-1".to_string()),
+        )
+        .await;
+        let elapsed = start.elapsed();
+
+        assert!(result.is_err(), "expected the invocation to time out");
+        // queue解放(150ms)にtimeout_ms(100ms)をまるまる足した250ms近くまでは
+        // 待たされないはず。差し引き漏れがあれば300ms超で引っかかる。
+        assert!(
+            elapsed < tokio::time::Duration::from_millis(280),
+            "elapsed {:?} suggests the queued wait time was not deducted from the deadline",
+            elapsed
+        );
+    }
+
+    #[tokio::test]
+    async fn ensure_capacity_does_not_overshoot_max_concurrency_under_concurrent_calls() {
+        // 生成に時間のかかるbackendに対して複数の呼び出しが同時にensure_capacityを
+        // 叩いても、全員がlen < max_concurrencyを観測してそれぞれinitしてしまい
+        // 上限を超える、という回帰を防ぐ。
+        let generator = TrackingRuntimeGenerator::with_init_delay(50);
+        let manager = RuntimeManager::new(generator, 10_000, 1);
+
+        let handles = (0..5).map(|_| {
+            let mut manager = manager.clone();
+            tokio::spawn(async move {
+                manager.ensure_capacity().await;
+            })
+        });
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        assert_eq!(manager.status().await.pool_size, 1);
+    }
+
+    #[tokio::test]
+    async fn ensure_capacity_stops_scaling_at_max_concurrency() {
+        let generator = TrackingRuntimeGenerator::new();
+        let mut manager = RuntimeManager::new(generator, 10_000, 2);
+
+        // idleなruntimeが無い限り、max_concurrencyに達するまで増やし続ける。
+        // 生成直後はidleなので、都度busyにして枯渇させないと1台で打ち止めになる。
+        for status in [1, 2, 2] {
+            manager.ensure_capacity().await;
+            let pool_size = manager.status().await.pool_size;
+            assert_eq!(pool_size, status);
+            for runtime in manager.status().await.runtimes {
+                manager
+                    .claim(
+                        &RuntimeId(runtime.id),
+                        &AWSRequestId(format!("busy-{}", pool_size)),
+                    )
+                    .await;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn streaming_response_forwards_chunks_in_order() {
+        use http_body_util::BodyExt;
+
+        let response_map = ResponseMap::default();
+        let aws_request_id = AWSRequestId("stream-test".to_string());
+        response_map.add_new_request(aws_request_id.clone()).await;
+
+        let stream = response_map
+            .start_stream(aws_request_id.clone())
+            .await
+            .expect("sender should still be available");
+        stream
+            .send_response_chunk(Bytes::from_static(b"chunk-1"))
+            .await
+            .unwrap();
+        stream
+            .send_response_chunk(Bytes::from_static(b"chunk-2"))
+            .await
+            .unwrap();
+        stream.finish();
+
+        let outcome = response_map
+            .get_response(&aws_request_id)
+            .await
+            .unwrap()
+            .unwrap();
+        let InvocationOutcome::Streaming(rx) = outcome else {
+            panic!("expected a streaming outcome");
+        };
+        let mut body = ChunkBody::new(rx);
+        let mut collected = Vec::new();
+        while let Some(frame) = body.frame().await {
+            collected.extend_from_slice(frame.unwrap().into_data().unwrap().as_ref());
+        }
+        assert_eq!(collected, b"chunk-1chunk-2");
+    }
+
+    #[tokio::test]
+    async fn runtime_list_tracks_state_transitions_and_invocation_count() {
+        let mut list = RuntimeList::new();
+        let runtime = Runtime::new(RuntimeId("runtime_0".to_string()), 0);
+        list.add(runtime.clone());
+        assert!(!list.has_idle());
+
+        list.mark_ready(&runtime.id);
+        assert!(list.is_idle(&runtime.id));
+
+        let aws_request_id = AWSRequestId("req-1".to_string());
+        list.claim(&runtime.id, &aws_request_id);
+        assert!(!list.is_idle(&runtime.id));
+        assert_eq!(list.find_by_request(&aws_request_id), Some(runtime.id.clone()));
+
+        list.release(&aws_request_id);
+        assert!(list.is_idle(&runtime.id));
+
+        let status = list.status();
+        assert_eq!(status.pool_size, 1);
+        assert_eq!(status.runtimes[0].invocations, 1);
+        assert_eq!(status.runtimes[0].state, "idle");
+    }
+
+    #[tokio::test]
+    async fn kill_escalates_to_sigkill_once_grace_period_elapses() {
+        // SIGTERMを無視するプロセスを、短い猶予で確実にSIGKILLへ昇格させられるか検証する
+        let generator = RuntimeProcessGenerator::with_grace_period(
+            "sh",
+            vec!["-c", "trap '' TERM; sleep 5"],
+            50,
+        );
+        let runtime = generator.init().await.unwrap();
+
+        let start = tokio::time::Instant::now();
+        generator.kill(&runtime.id).await.unwrap();
+        // SIGKILLへ昇格するまでの猶予以上はかかるが、sleep 5の満了までは待たされない
+        assert!(start.elapsed() >= tokio::time::Duration::from_millis(50));
+        assert!(start.elapsed() < tokio::time::Duration::from_secs(3));
+    }
 }
@@ -13,7 +13,7 @@ pub struct StatusResponse {
     pub status: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct ErrorResponse {
     pub error_message: String,
@@ -27,12 +27,38 @@ pub enum InvocationNextResponse {
     EventResponse(EventResponse),
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EventErrorRequest {
+    pub error_message: String,
+    pub error_type: String,
+    #[serde(default)]
+    pub stack_trace: String,
+}
+
+impl From<EventErrorRequest> for ErrorResponse {
+    fn from(req: EventErrorRequest) -> Self {
+        ErrorResponse {
+            error_message: req.error_message,
+            error_type: req.error_type,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PoolStatus {
+    pub pool_size: usize,
+    pub runtimes: Vec<RuntimeStatus>,
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
-pub struct ErrorRequest {
-    error_message: String,
-    error_type: String,
-    stack_trace: String,
+pub struct RuntimeStatus {
+    pub id: String,
+    pub state: String,
+    pub age_ms: u64,
+    pub invocations: u64,
 }
 
 #[derive(Debug, Serialize, PartialEq, Clone, Deserialize)]
@@ -41,6 +67,7 @@ pub struct RequestEvent(pub Map<String, Value>);
 #[derive(Debug, Serialize, PartialEq, Deserialize)]
 pub struct EventResponse(pub Map<String, Value>);
 
+// /next で返すペイロードは受け取ったリクエストのボディをそのまま載せる
 impl From<RequestEvent> for EventResponse {
     fn from(event: RequestEvent) -> Self {
         EventResponse(event.0)